@@ -0,0 +1,81 @@
+use lru::LruCache;
+use std::sync::Mutex;
+
+/// A bounded, expiry-aware cache of already-verified tokens, keyed on the
+/// raw bearer token string. `SimpleAuthMiddleware` consults it before
+/// calling `verify_and_decode`/`T::check` so a hot token doesn't pay for
+/// repeated signature verification on every request.
+pub struct TokenCache<I> {
+    entries: Mutex<LruCache<String, (I, i64)>>,
+}
+
+impl<I: Clone> TokenCache<I> {
+    pub fn new(size: usize) -> Self {
+        TokenCache {
+            entries: Mutex::new(LruCache::new(size)),
+        }
+    }
+
+    /// Returns the cached item for `token` if it is present and its `exp`
+    /// is still in the future, evicting and reporting a miss otherwise so
+    /// an expired token always falls back to full verification. Uses `get`
+    /// rather than `peek` so a hit also bumps the token's LRU recency —
+    /// otherwise eviction would be by insertion order, and a token read on
+    /// every request could still be evicted ahead of a colder one.
+    pub fn get(&self, token: &str, now: i64) -> Option<I> {
+        let mut entries = self.entries.lock().expect("token cache lock poisoned");
+        match entries.get(token) {
+            Some((item, exp)) if *exp > now => Some(item.clone()),
+            Some(_) => {
+                entries.pop(token);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, token: String, item: I, exp: i64) {
+        self.entries
+            .lock()
+            .expect("token cache lock poisoned")
+            .put(token, (item, exp));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cache_miss_before_insert() {
+        let cache = TokenCache::new(2);
+        assert_eq!(cache.get("a-token", 0), None);
+    }
+
+    #[test]
+    fn test_cache_hit() {
+        let cache = TokenCache::new(2);
+        cache.insert("a-token".to_owned(), "claims".to_owned(), 100);
+        assert_eq!(cache.get("a-token", 50), Some("claims".to_owned()));
+    }
+
+    #[test]
+    fn test_cache_evicts_expired() {
+        let cache = TokenCache::new(2);
+        cache.insert("a-token".to_owned(), "claims".to_owned(), 100);
+        assert_eq!(cache.get("a-token", 150), None);
+        assert_eq!(cache.get("a-token", 50), None);
+    }
+
+    #[test]
+    fn test_cache_get_refreshes_recency() {
+        let cache = TokenCache::new(2);
+        cache.insert("a-token".to_owned(), "a".to_owned(), 100);
+        cache.insert("b-token".to_owned(), "b".to_owned(), 100);
+        // Touching "a-token" should make "b-token" the least recently used.
+        assert_eq!(cache.get("a-token", 0), Some("a".to_owned()));
+        cache.insert("c-token".to_owned(), "c".to_owned(), 100);
+        assert_eq!(cache.get("b-token", 0), None);
+        assert_eq!(cache.get("a-token", 0), Some("a".to_owned()));
+    }
+}
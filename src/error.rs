@@ -0,0 +1,41 @@
+use actix_web::error::ResponseError;
+use actix_web::http::header;
+use actix_web::HttpResponse;
+use failure::Fail;
+
+#[derive(Fail, Debug)]
+pub enum ServiceError {
+    /// No token could be found in any of the configured sources.
+    #[fail(display = "missing bearer token")]
+    Unauthorized,
+    #[fail(display = "Forbidden")]
+    Forbidden,
+    /// The token decoded but failed a temporal validation: it is expired or
+    /// not yet valid (RFC 6750 `error="invalid_token", error_description="expired"`).
+    #[fail(display = "expired token")]
+    ExpiredToken,
+    /// The token could not be decoded/verified (bad signature, malformed
+    /// claims, …) or failed validation for a non-temporal reason.
+    #[fail(display = "invalid token")]
+    InvalidToken,
+}
+
+impl ResponseError for ServiceError {
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            ServiceError::Unauthorized => HttpResponse::Unauthorized()
+                .header(header::WWW_AUTHENTICATE, "Bearer")
+                .finish(),
+            ServiceError::Forbidden => HttpResponse::Forbidden().finish(),
+            ServiceError::ExpiredToken => HttpResponse::Unauthorized()
+                .header(
+                    header::WWW_AUTHENTICATE,
+                    r#"Bearer error="invalid_token", error_description="expired""#,
+                )
+                .finish(),
+            ServiceError::InvalidToken => HttpResponse::Unauthorized()
+                .header(header::WWW_AUTHENTICATE, r#"Bearer error="invalid_token""#)
+                .finish(),
+        }
+    }
+}
@@ -0,0 +1,240 @@
+use crate::check::TokenChecker;
+use crate::error::ServiceError;
+use crate::simple::verify_claims;
+use crate::token_source::TokenSource;
+use crate::BoxFut;
+use actix_service::Service;
+use actix_service::Transform;
+use actix_web::dev::ServiceRequest;
+use actix_web::dev::ServiceResponse;
+use actix_web::Error;
+use biscuit::ClaimsSet;
+use biscuit::ValidationOptions;
+use futures::future;
+use futures::future::Ready;
+use futures::task::Context;
+use futures::task::Poll;
+use serde_json::Value;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// Gates access behind a required set of values (e.g. scopes or group
+/// names) found in a claim of the token's private claims JSON, on top of
+/// the same verify-and-check flow `SimpleAuth` performs.
+#[derive(Clone)]
+pub struct ScopeAuth<T: TokenChecker<Item = ClaimsSet<Value>> + 'static> {
+    pub checker: T,
+    pub validation_options: ValidationOptions,
+    pub claim: String,
+    pub required: Arc<Vec<String>>,
+    pub token_sources: Vec<TokenSource>,
+}
+
+impl<T: TokenChecker<Item = ClaimsSet<Value>> + 'static> ScopeAuth<T> {
+    pub fn new(checker: T, validation_options: ValidationOptions, required: &[&str]) -> Self {
+        ScopeAuth {
+            checker,
+            validation_options,
+            claim: "scope".to_owned(),
+            required: Arc::new(required.iter().map(|s| (*s).to_owned()).collect()),
+            token_sources: vec![TokenSource::header()],
+        }
+    }
+
+    pub fn with_claim(mut self, claim: &str) -> Self {
+        self.claim = claim.to_owned();
+        self
+    }
+
+    /// See `SimpleAuth::with_token_sources`.
+    pub fn with_token_sources(mut self, token_sources: Vec<TokenSource>) -> Self {
+        self.token_sources = token_sources;
+        self
+    }
+}
+
+#[derive(Clone)]
+pub struct ScopeAuthMiddleware<S, T: TokenChecker<Item = ClaimsSet<Value>> + 'static> {
+    // See `SimpleAuthMiddleware::service` for why this is `Rc`, not `Arc`.
+    pub service: Rc<RefCell<S>>,
+    pub checker: Arc<T>,
+    pub validation_options: ValidationOptions,
+    pub claim: String,
+    pub required: Arc<Vec<String>>,
+    pub token_sources: Arc<Vec<TokenSource>>,
+}
+
+impl<S, B: 'static, T: TokenChecker<Item = ClaimsSet<Value>> + Clone + 'static> Transform<S>
+    for ScopeAuth<T>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = ScopeAuthMiddleware<S, T>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(ScopeAuthMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            checker: Arc::new(self.checker.clone()),
+            validation_options: self.validation_options.clone(),
+            claim: self.claim.clone(),
+            required: self.required.clone(),
+            token_sources: Arc::new(self.token_sources.clone()),
+        })
+    }
+}
+
+impl<S, B, T: TokenChecker<Item = ClaimsSet<Value>> + 'static> Service for ScopeAuthMiddleware<S, T>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = BoxFut<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        (*self.service).borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        if req.method() == "OPTIONS" {
+            return Box::pin(self.service.borrow_mut().call(req));
+        }
+
+        let svc = self.service.clone();
+        let checker = self.checker.clone();
+        let validation_options = self.validation_options.clone();
+        let token_sources = self.token_sources.clone();
+        let claim = self.claim.clone();
+        let required = self.required.clone();
+
+        Box::pin(async move {
+            let claim_set =
+                verify_claims(&req, &*checker, validation_options, &token_sources, None, None)
+                    .await?;
+            if has_required(&claim_set, &claim, &required) {
+                let fut = svc.borrow_mut().call(req);
+                fut.await
+            } else {
+                Err(ServiceError::Forbidden.into())
+            }
+        })
+    }
+}
+
+fn has_required(claim_set: &ClaimsSet<Value>, claim: &str, required: &[String]) -> bool {
+    let held: Vec<&str> = match claim_set.private.get(claim).and_then(Value::as_array) {
+        Some(values) => values.iter().filter_map(Value::as_str).collect(),
+        None => return false,
+    };
+    required.iter().all(|r| held.contains(&r.as_str()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_service::IntoService;
+    use actix_web::test::TestRequest;
+    use actix_web::HttpResponse;
+    use failure::Error;
+    use futures::future::ok;
+    use futures::future::BoxFuture;
+    use serde_json::json;
+
+    #[derive(Default, Clone)]
+    struct FakeChecker {
+        pub claim_set: Option<ClaimsSet<Value>>,
+    }
+
+    impl TokenChecker for FakeChecker {
+        type Item = ClaimsSet<Value>;
+        type Future = BoxFuture<'static, Result<Self::Item, Error>>;
+        fn verify_and_decode(&self, _token: String) -> Self::Future {
+            match &self.claim_set {
+                Some(cs) => Box::pin(future::ok(cs.clone())),
+                None => Box::pin(future::err(ServiceError::Unauthorized.into())),
+            }
+        }
+        fn check(item: &Self::Item, validation_options: ValidationOptions) -> Result<(), Error> {
+            item.registered
+                .validate(validation_options)
+                .map_err(Into::into)
+        }
+    }
+
+    fn claims_with_scope(scope: &[&str]) -> ClaimsSet<Value> {
+        ClaimsSet {
+            registered: Default::default(),
+            private: json!({ "scope": scope }),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_scope_auth_forbidden() {
+        let srv = |req: ServiceRequest| ok(req.into_response(HttpResponse::Ok()));
+        let auth_middleware = ScopeAuth::new(
+            FakeChecker {
+                claim_set: Some(claims_with_scope(&["other"])),
+            },
+            ValidationOptions::default(),
+            &["mozilliansorg_nda"],
+        );
+        let mut srv = auth_middleware
+            .new_transform(srv.into_service())
+            .await
+            .unwrap();
+        let req = TestRequest::with_header("AUTHORIZATION", "Bearer sometoken").to_srv_request();
+        let res = srv.call(req).await;
+        assert!(res.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_scope_auth_authorized() {
+        let srv = |req: ServiceRequest| ok(req.into_response(HttpResponse::Ok()));
+        let auth_middleware = ScopeAuth::new(
+            FakeChecker {
+                claim_set: Some(claims_with_scope(&["mozilliansorg_nda"])),
+            },
+            ValidationOptions::default(),
+            &["mozilliansorg_nda"],
+        );
+        let mut srv = auth_middleware
+            .new_transform(srv.into_service())
+            .await
+            .unwrap();
+        let req = TestRequest::with_header("AUTHORIZATION", "Bearer sometoken").to_srv_request();
+        let res = srv.call(req).await;
+        assert!(res.is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn test_scope_auth_claims_available_to_extractor() {
+        let srv = |req: ServiceRequest| {
+            assert!(req.extensions().get::<ClaimsSet<Value>>().is_some());
+            ok(req.into_response(HttpResponse::Ok()))
+        };
+        let auth_middleware = ScopeAuth::new(
+            FakeChecker {
+                claim_set: Some(claims_with_scope(&["mozilliansorg_nda"])),
+            },
+            ValidationOptions::default(),
+            &["mozilliansorg_nda"],
+        );
+        let mut srv = auth_middleware
+            .new_transform(srv.into_service())
+            .await
+            .unwrap();
+        let req = TestRequest::with_header("AUTHORIZATION", "Bearer sometoken").to_srv_request();
+        let res = srv.call(req).await;
+        assert!(res.is_ok());
+    }
+}
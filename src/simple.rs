@@ -1,30 +1,92 @@
+use crate::cache::TokenCache;
 use crate::check::TokenChecker;
 use crate::error::ServiceError;
+use crate::token_source::extract_token;
+use crate::token_source::TokenSource;
 use crate::BoxFut;
 use actix_service::Service;
 use actix_service::Transform;
+use actix_web::dev::Payload;
 use actix_web::dev::ServiceRequest;
 use actix_web::dev::ServiceResponse;
 use actix_web::Error;
+use actix_web::FromRequest;
+use actix_web::HttpRequest;
+use biscuit::ValidationError;
 use biscuit::ValidationOptions;
+use chrono::Utc;
 use futures::future;
 use futures::future::Ready;
 use futures::task::Context;
 use futures::task::Poll;
-use futures::TryFutureExt;
 use std::cell::RefCell;
+use std::ops::Deref;
+use std::rc::Rc;
 use std::sync::Arc;
 
+/// A post-verification predicate that gets the decoded claims and the
+/// original request, and can veto an otherwise valid token.
+pub type ProcessFn<T> =
+    Arc<dyn Fn(&<T as TokenChecker>::Item, &ServiceRequest) -> Result<(), ServiceError> + Send + Sync>;
+
 #[derive(Clone)]
 pub struct SimpleAuth<T: TokenChecker + 'static> {
     pub checker: T,
     pub validation_options: ValidationOptions,
+    pub process_fn: Option<ProcessFn<T>>,
+    pub cache: Option<Arc<TokenCache<T::Item>>>,
+    pub token_sources: Vec<TokenSource>,
+}
+
+impl<T: TokenChecker + 'static> SimpleAuth<T> {
+    pub fn new(checker: T, validation_options: ValidationOptions) -> Self {
+        SimpleAuth {
+            checker,
+            validation_options,
+            process_fn: None,
+            cache: None,
+            token_sources: vec![TokenSource::header()],
+        }
+    }
+
+    /// Tries each source in order to find the bearer token, e.g. an
+    /// `Authorization` header for APIs and an `access_token` cookie for
+    /// server-rendered pages or WebSocket upgrades that can't set headers.
+    pub fn with_token_sources(mut self, token_sources: Vec<TokenSource>) -> Self {
+        self.token_sources = token_sources;
+        self
+    }
+
+    /// Runs `f` after a token has been verified and decoded, letting a
+    /// route apply a final allow/deny decision (e.g. checking `aud` or a
+    /// path parameter against a claim) without a dedicated `TokenChecker`.
+    pub fn with_fn<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&T::Item, &ServiceRequest) -> Result<(), ServiceError> + Send + Sync + 'static,
+    {
+        self.process_fn = Some(Arc::new(f));
+        self
+    }
+
+    /// Caches up to `size` already-verified tokens, keyed on the raw
+    /// bearer token and evicted once their own `exp` passes, so a hot
+    /// token doesn't re-run `verify_and_decode`/`T::check` on every call.
+    pub fn with_cache_size(mut self, size: usize) -> Self {
+        self.cache = Some(Arc::new(TokenCache::new(size)));
+        self
+    }
 }
+
 #[derive(Clone)]
 pub struct SimpleAuthMiddleware<S, T: TokenChecker + 'static> {
-    pub service: Arc<RefCell<S>>,
+    // `RefCell` is `!Sync`, so wrapping it in `Arc` is a soundness hazard;
+    // `Rc` makes that single-threaded-per-worker assumption explicit instead.
+    pub service: Rc<RefCell<S>>,
     pub checker: Arc<T>,
     pub validation_options: ValidationOptions,
+    pub process_fn: Option<ProcessFn<T>>,
+    pub cache: Option<Arc<TokenCache<T::Item>>>,
+    pub token_sources: Arc<Vec<TokenSource>>,
 }
 
 impl<S, B: 'static, T: TokenChecker + Clone + 'static> Transform<S> for SimpleAuth<T>
@@ -41,9 +103,12 @@ where
 
     fn new_transform(&self, service: S) -> Self::Future {
         future::ok(SimpleAuthMiddleware {
-            service: Arc::new(RefCell::new(service)),
+            service: Rc::new(RefCell::new(service)),
             checker: Arc::new(self.checker.clone()),
             validation_options: self.validation_options.clone(),
+            process_fn: self.process_fn.clone(),
+            cache: self.cache.clone(),
+            token_sources: Arc::new(self.token_sources.clone()),
         })
     }
 }
@@ -53,6 +118,7 @@ where
     S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
     B: 'static,
+    T::Item: Clone,
 {
     type Request = ServiceRequest;
     type Response = ServiceResponse<B>;
@@ -68,35 +134,117 @@ where
             return Box::pin(self.service.borrow_mut().call(req));
         }
 
-        let auth_header = match req.headers().get("AUTHORIZATION") {
-            Some(value) => value.to_str().ok(),
-            None => return Box::pin(future::err(ServiceError::Unauthorized.into())),
-        };
+        let svc = self.service.clone();
+        let checker = self.checker.clone();
+        let validation_options = self.validation_options.clone();
+        let process_fn = self.process_fn.clone();
+        let cache = self.cache.clone();
+        let token_sources = self.token_sources.clone();
+
+        Box::pin(async move {
+            verify_claims(
+                &req,
+                &*checker,
+                validation_options,
+                &token_sources,
+                process_fn.as_ref(),
+                cache.as_ref(),
+            )
+            .await?;
+            let fut = svc.borrow_mut().call(req);
+            fut.await
+        })
+    }
+}
+
+/// Extracts a bearer token from `req` via `token_sources`, verifies and
+/// decodes it with `checker` (consulting `cache` first and, on a miss,
+/// populating it only if the claims carry an `exp`), runs `T::check` and
+/// `process_fn`, and stashes the decoded
+/// claims in `req`'s extensions — the shared verify path `SimpleAuth` and
+/// `ScopeAuth` both build their `call` on.
+pub(crate) async fn verify_claims<T: TokenChecker + 'static>(
+    req: &ServiceRequest,
+    checker: &T,
+    validation_options: ValidationOptions,
+    token_sources: &[TokenSource],
+    process_fn: Option<&ProcessFn<T>>,
+    cache: Option<&Arc<TokenCache<T::Item>>>,
+) -> Result<T::Item, ServiceError>
+where
+    T::Item: Clone,
+{
+    let token = extract_token(req, token_sources).ok_or(ServiceError::Unauthorized)?;
 
-        if let Some(auth_header) = auth_header {
-            if let Some(token) = get_token(auth_header) {
-                let svc = self.service.clone();
-                let validation_options = self.validation_options.clone();
-                let fut = self.checker.verify_and_decode(token.to_owned());
-                return Box::pin(async move {
-                    let claim_set = fut.map_err(Error::from).await?;
-                    match T::check(&claim_set, validation_options) {
-                        Ok(_) => svc.borrow_mut().call(req).await,
-                        Err(_) => Err(ServiceError::Unauthorized.into()),
-                    }
-                });
+    if let Some(cache) = cache {
+        if let Some(claim_set) = cache.get(&token, Utc::now().timestamp()) {
+            if let Some(process_fn) = process_fn {
+                process_fn(&claim_set, req)?;
             }
+            req.extensions_mut().insert(claim_set.clone());
+            return Ok(claim_set);
         }
-        Box::pin(future::err(ServiceError::Unauthorized.into()))
+    }
+
+    let claim_set = checker
+        .verify_and_decode(token.clone())
+        .await
+        .map_err(|_| ServiceError::InvalidToken)?;
+    T::check(&claim_set, validation_options).map_err(|e| token_validation_error(&e))?;
+    if let Some(process_fn) = process_fn {
+        process_fn(&claim_set, req)?;
+    }
+    if let Some(cache) = cache {
+        if let Some(exp) = claim_set.registered.expiry {
+            cache.insert(token, claim_set.clone(), exp.timestamp());
+        }
+    }
+    req.extensions_mut().insert(claim_set.clone());
+    Ok(claim_set)
+}
+
+/// Maps a `T::check` failure to the RFC 6750 error it should surface:
+/// a temporal (expiry/not-yet-valid) failure reports as an expired token,
+/// anything else is reported as a plain invalid token.
+fn token_validation_error(e: &failure::Error) -> ServiceError {
+    match e.downcast_ref::<ValidationError>() {
+        Some(ValidationError::Temporal(_)) => ServiceError::ExpiredToken,
+        _ => ServiceError::InvalidToken,
     }
 }
 
-fn get_token(auth_header: &str) -> Option<&str> {
-    match auth_header.get(0..7) {
-        Some("Bearer ") => auth_header.get(7..),
-        _ => None,
+/// Extracts the `ClaimsSet` a `SimpleAuthMiddleware` stashed in the request
+/// extensions after a successful verification, so handlers can read the
+/// authenticated claims without re-parsing the bearer token.
+///
+/// `C` must be exactly the `TokenChecker::Item` the middleware inserted
+/// (e.g. `AuthClaims<ClaimsSet<Value>>` for a checker with
+/// `type Item = ClaimsSet<Value>`), not some type nested inside it —
+/// `extensions().get::<C>()` looks up by the stored type, so a mismatched
+/// `C` misses and resolves to `Unauthorized` rather than failing to compile.
+pub struct AuthClaims<C>(pub C);
+
+impl<C> Deref for AuthClaims<C> {
+    type Target = C;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
     }
 }
+
+impl<C: Clone + 'static> FromRequest for AuthClaims<C> {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        match req.extensions().get::<C>() {
+            Some(claim_set) => future::ok(AuthClaims(claim_set.clone())),
+            None => future::err(ServiceError::Unauthorized.into()),
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -104,10 +252,14 @@ mod test {
     use actix_web::test::TestRequest;
     use actix_web::HttpResponse;
     use biscuit::ClaimsSet;
+    use chrono::TimeZone;
     use failure::Error;
     use futures::future::ok;
+    use futures::future::poll_fn;
     use futures::future::BoxFuture;
     use serde_json::Value;
+    use std::future::Future;
+    use std::pin::Pin;
 
     #[derive(Default, Clone)]
     struct FakeChecker {
@@ -138,18 +290,15 @@ mod test {
         }
     }
 
-    #[test]
-    fn test_get_token() {
-        let token = "Bearer FOOBAR…";
-        assert_eq!(get_token(token), Some("FOOBAR…"));
-    }
-
     #[actix_rt::test]
     async fn test_middleware_no_token() {
         let srv = |req: ServiceRequest| ok(req.into_response(HttpResponse::Ok()));
         let auth_middleware = SimpleAuth {
             checker: FakeChecker::default(),
             validation_options: ValidationOptions::default(),
+            process_fn: None,
+            cache: None,
+            token_sources: vec![TokenSource::header()],
         };
         let mut srv = auth_middleware
             .new_transform(srv.into_service())
@@ -172,6 +321,9 @@ mod test {
                 token: None,
             },
             validation_options: ValidationOptions::default(),
+            process_fn: None,
+            cache: None,
+            token_sources: vec![TokenSource::header()],
         };
         let mut srv = auth_middleware
             .new_transform(srv.into_service())
@@ -194,6 +346,9 @@ mod test {
                 token: None,
             },
             validation_options: ValidationOptions::default(),
+            process_fn: None,
+            cache: None,
+            token_sources: vec![TokenSource::header()],
         };
         let mut srv = auth_middleware
             .new_transform(srv.into_service())
@@ -203,4 +358,240 @@ mod test {
         let res = srv.call(req).await;
         assert!(res.is_ok());
     }
+
+    #[actix_rt::test]
+    async fn test_auth_claims_from_request() {
+        let req = TestRequest::default().to_http_request();
+        req.extensions_mut().insert(ClaimsSet {
+            registered: Default::default(),
+            private: Value::String("ford".to_owned()),
+        });
+        let claims = AuthClaims::<ClaimsSet<Value>>::from_request(&req, &mut Payload::None)
+            .await
+            .unwrap();
+        assert_eq!(claims.private, Value::String("ford".to_owned()));
+    }
+
+    #[actix_rt::test]
+    async fn test_auth_claims_missing() {
+        let req = TestRequest::default().to_http_request();
+        let claims =
+            AuthClaims::<ClaimsSet<Value>>::from_request(&req, &mut Payload::None).await;
+        assert!(claims.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_process_fn_denies() {
+        let srv = |req: ServiceRequest| ok(req.into_response(HttpResponse::Ok()));
+        let auth_middleware = SimpleAuth::new(
+            FakeChecker {
+                claim_set: Some(ClaimsSet {
+                    registered: Default::default(),
+                    private: Value::default(),
+                }),
+                token: None,
+            },
+            ValidationOptions::default(),
+        )
+        .with_fn(|_claims, _req| Err(ServiceError::Unauthorized));
+        let mut srv = auth_middleware
+            .new_transform(srv.into_service())
+            .await
+            .unwrap();
+        let req = TestRequest::with_header("AUTHORIZATION", "Bearer somethingfun").to_srv_request();
+        let res = srv.call(req).await;
+        assert!(res.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_process_fn_allows() {
+        let srv = |req: ServiceRequest| ok(req.into_response(HttpResponse::Ok()));
+        let auth_middleware = SimpleAuth::new(
+            FakeChecker {
+                claim_set: Some(ClaimsSet {
+                    registered: Default::default(),
+                    private: Value::default(),
+                }),
+                token: None,
+            },
+            ValidationOptions::default(),
+        )
+        .with_fn(|_claims, req| {
+            if req.path() == "/" {
+                Ok(())
+            } else {
+                Err(ServiceError::Unauthorized)
+            }
+        });
+        let mut srv = auth_middleware
+            .new_transform(srv.into_service())
+            .await
+            .unwrap();
+        let req = TestRequest::with_header("AUTHORIZATION", "Bearer somethingfun").to_srv_request();
+        let res = srv.call(req).await;
+        assert!(res.is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn test_middleware_query_token_source() {
+        let srv = |req: ServiceRequest| ok(req.into_response(HttpResponse::Ok()));
+        let auth_middleware = SimpleAuth::new(
+            FakeChecker {
+                claim_set: Some(ClaimsSet {
+                    registered: Default::default(),
+                    private: Value::default(),
+                }),
+                token: None,
+            },
+            ValidationOptions::default(),
+        )
+        .with_token_sources(vec![TokenSource::Query("access_token".to_owned())]);
+        let mut srv = auth_middleware
+            .new_transform(srv.into_service())
+            .await
+            .unwrap();
+        let req = TestRequest::with_uri("/?access_token=somethingfun").to_srv_request();
+        let res = srv.call(req).await;
+        assert!(res.is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn test_middleware_missing_token_challenge() {
+        let srv = |req: ServiceRequest| ok(req.into_response(HttpResponse::Ok()));
+        let auth_middleware = SimpleAuth::new(FakeChecker::default(), ValidationOptions::default());
+        let mut srv = auth_middleware
+            .new_transform(srv.into_service())
+            .await
+            .unwrap();
+        let req = TestRequest::default().to_srv_request();
+        let res = srv.call(req).await;
+        let err = res.unwrap_err();
+        let resp = err.as_response_error().error_response();
+        assert_eq!(resp.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            resp.headers().get(actix_web::http::header::WWW_AUTHENTICATE).unwrap(),
+            "Bearer"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_middleware_invalid_token_challenge() {
+        let srv = |req: ServiceRequest| ok(req.into_response(HttpResponse::Ok()));
+        let auth_middleware = SimpleAuth::new(FakeChecker::default(), ValidationOptions::default());
+        let mut srv = auth_middleware
+            .new_transform(srv.into_service())
+            .await
+            .unwrap();
+        let req = TestRequest::with_header("AUTHORIZATION", "Bearer somethingfun").to_srv_request();
+        let res = srv.call(req).await;
+        let err = res.unwrap_err();
+        let resp = err.as_response_error().error_response();
+        assert_eq!(resp.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+        let challenge = resp
+            .headers()
+            .get(actix_web::http::header::WWW_AUTHENTICATE)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(challenge.contains("invalid_token"));
+        assert!(!challenge.contains("expired"));
+    }
+
+    #[actix_rt::test]
+    async fn test_middleware_expired_token_challenge() {
+        let srv = |req: ServiceRequest| ok(req.into_response(HttpResponse::Ok()));
+        let claims = ClaimsSet {
+            registered: biscuit::RegisteredClaims {
+                expiry: Some(biscuit::Timestamp::from(
+                    chrono::Utc.ymd(2000, 1, 1).and_hms(0, 0, 0),
+                )),
+                ..Default::default()
+            },
+            private: Value::default(),
+        };
+        let auth_middleware = SimpleAuth::new(
+            FakeChecker {
+                claim_set: Some(claims),
+                token: None,
+            },
+            ValidationOptions::default(),
+        );
+        let mut srv = auth_middleware
+            .new_transform(srv.into_service())
+            .await
+            .unwrap();
+        let req = TestRequest::with_header("AUTHORIZATION", "Bearer somethingfun").to_srv_request();
+        let res = srv.call(req).await;
+        let err = res.unwrap_err();
+        let resp = err.as_response_error().error_response();
+        assert_eq!(resp.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+        let challenge = resp
+            .headers()
+            .get(actix_web::http::header::WWW_AUTHENTICATE)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(challenge.contains("expired"));
+    }
+
+    /// A leaf service future that returns `Pending` on its first poll and
+    /// only resolves on the second, so a caller that awaits it mid-statement
+    /// is actually suspended — unlike `ok(..)`, which resolves on the first
+    /// poll and never forces the two concurrent `call`s below to overlap.
+    struct PendingOnce {
+        req: Option<ServiceRequest>,
+        polled: bool,
+    }
+
+    impl Future for PendingOnce {
+        type Output = Result<ServiceResponse, actix_web::Error>;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+            if self.polled {
+                let req = self.req.take().expect("polled after completion");
+                Poll::Ready(Ok(req.into_response(HttpResponse::Ok())))
+            } else {
+                self.polled = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_concurrent_calls_on_one_worker() {
+        let srv = |req: ServiceRequest| PendingOnce {
+            req: Some(req),
+            polled: false,
+        };
+        let auth_middleware = SimpleAuth::new(
+            FakeChecker {
+                claim_set: Some(ClaimsSet {
+                    registered: Default::default(),
+                    private: Value::default(),
+                }),
+                token: None,
+            },
+            ValidationOptions::default(),
+        );
+        let mut srv1 = auth_middleware
+            .new_transform(srv.into_service())
+            .await
+            .unwrap();
+        let mut srv2 = srv1.clone();
+
+        poll_fn(|cx| srv1.poll_ready(cx)).await.unwrap();
+        poll_fn(|cx| srv2.poll_ready(cx)).await.unwrap();
+
+        let req1 = TestRequest::with_header("AUTHORIZATION", "Bearer somethingfun").to_srv_request();
+        let req2 = TestRequest::with_header("AUTHORIZATION", "Bearer somethingfun").to_srv_request();
+
+        // `call` on req1 suspends mid-statement on `PendingOnce`'s first
+        // poll; if the `RefCell` borrow is still held across that
+        // suspension, polling req2's `call` on the same worker panics on
+        // the already-borrowed cell instead of completing.
+        let (res1, res2) = futures::join!(srv1.call(req1), srv2.call(req2));
+        assert!(res1.is_ok());
+        assert!(res2.is_ok());
+    }
 }
@@ -0,0 +1,97 @@
+use actix_web::dev::ServiceRequest;
+use actix_web::HttpMessage;
+
+/// Where a bearer token may be read from. `SimpleAuth` tries a configured
+/// list of these, in order, so the same middleware can serve an API
+/// (`Authorization: Bearer ...`) and a browser/WebSocket flow that can't
+/// set arbitrary headers (an `access_token` cookie or query param).
+#[derive(Clone, Debug, PartialEq)]
+pub enum TokenSource {
+    Header { name: String, scheme: String },
+    Cookie(String),
+    Query(String),
+}
+
+impl TokenSource {
+    /// The default and previously only behavior: `Authorization: Bearer <token>`.
+    pub fn header() -> Self {
+        TokenSource::Header {
+            name: "AUTHORIZATION".to_owned(),
+            scheme: "Bearer ".to_owned(),
+        }
+    }
+
+    fn extract(&self, req: &ServiceRequest) -> Option<String> {
+        match self {
+            TokenSource::Header { name, scheme } => {
+                let value = req.headers().get(name)?.to_str().ok()?;
+                value.strip_prefix(scheme.as_str()).map(str::to_owned)
+            }
+            TokenSource::Cookie(name) => req.cookie(name).map(|c| c.value().to_owned()),
+            TokenSource::Query(name) => {
+                req.uri()
+                    .query()
+                    .and_then(|query| {
+                        query.split('&').find_map(|pair| {
+                            let mut parts = pair.splitn(2, '=');
+                            let key = parts.next()?;
+                            let value = parts.next()?;
+                            if key == name {
+                                Some(value.to_owned())
+                            } else {
+                                None
+                            }
+                        })
+                    })
+            }
+        }
+    }
+}
+
+impl Default for TokenSource {
+    fn default() -> Self {
+        TokenSource::header()
+    }
+}
+
+/// Tries each source in order, returning the first token found.
+pub fn extract_token(req: &ServiceRequest, sources: &[TokenSource]) -> Option<String> {
+    sources.iter().find_map(|source| source.extract(req))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_header_source() {
+        let req = TestRequest::with_header("AUTHORIZATION", "Bearer a-token").to_srv_request();
+        assert_eq!(
+            extract_token(&req, &[TokenSource::header()]),
+            Some("a-token".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_query_source() {
+        let req = TestRequest::with_uri("/?access_token=a-token").to_srv_request();
+        assert_eq!(
+            extract_token(&req, &[TokenSource::Query("access_token".to_owned())]),
+            Some("a-token".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_falls_through_to_next_source() {
+        let req = TestRequest::with_uri("/?access_token=a-token").to_srv_request();
+        let sources = [TokenSource::header(), TokenSource::Query("access_token".to_owned())];
+        assert_eq!(extract_token(&req, &sources), Some("a-token".to_owned()));
+    }
+
+    #[test]
+    fn test_no_match() {
+        let req = TestRequest::default().to_srv_request();
+        assert_eq!(extract_token(&req, &[TokenSource::header()]), None);
+    }
+}